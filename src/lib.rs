@@ -0,0 +1,499 @@
+//! Find state groups in a synapse database that are not referenced by any
+//! events (or by other state groups that are referenced by events).
+//!
+//! The types returned by [`scan`] are the stable, machine readable interface
+//! of this crate: they derive `Serialize`/`Deserialize`, are marked
+//! `#[non_exhaustive]` so that fields can be added without a breaking
+//! release, and serialized reports carry [`SCAN_REPORT_VERSION`] so that
+//! consumers can detect incompatible changes to the format.
+
+use postgres::types::ToSql;
+use postgres::{Client, NoTls};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+
+/// The version of the serialized [`ScanReport`] format. This is bumped
+/// whenever a field is removed, renamed or changes meaning.
+///
+/// Fields may be added without bumping it, but every added field must be
+/// marked `#[serde(default)]` so that reports serialized before the field
+/// existed still deserialize.
+pub const SCAN_REPORT_VERSION: u32 = 1;
+
+/// An error that occurred while scanning.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ScanError {
+    /// Connecting to or querying the database failed
+    Database(Box<dyn Error + Send + Sync>),
+}
+
+impl ScanError {
+    fn database(err: postgres::Error) -> ScanError {
+        ScanError::Database(Box::new(err))
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::Database(err) => write!(f, "database error: {}", err),
+        }
+    }
+}
+
+impl Error for ScanError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScanError::Database(err) => Some(&**err),
+        }
+    }
+}
+
+/// Progress updates reported by [`scan`] as it runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ScanEvent {
+    /// A batch of rows was retrieved while fetching the initial set of state
+    /// groups, with the number of rows in the batch
+    RowsRetrieved(u64),
+    /// The initial set of state groups was fetched, with the number of groups
+    StateGroupsFetched(usize),
+    /// Some referenced state groups weren't in the initial set and are about
+    /// to be fetched, with the number of groups
+    FetchingMissing(usize),
+    /// Missing state groups were fetched, with the number that were found
+    MissingFetched(usize),
+    /// Some missing state groups couldn't be found, with the number of groups
+    MissingNotFound(usize),
+    /// All state groups have been fetched, with the total number of groups
+    TotalStateGroups(usize),
+}
+
+/// The result of scanning the database for unreferenced state groups.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ScanReport {
+    /// The version of the report format, see [`SCAN_REPORT_VERSION`].
+    pub version: u32,
+    /// The room the scan was limited to, if any
+    pub room_id: Option<String>,
+    /// The total number of state groups inspected, including any that were
+    /// fetched because they were referenced from outside the room.
+    pub total_state_groups: u64,
+    /// The state groups that are not referenced, ordered by state group
+    pub candidates: Vec<Candidate>,
+    /// Per room totals, ordered by room ID
+    pub rooms: Vec<RoomStats>,
+}
+
+/// A state group that is not referenced by any events.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Candidate {
+    /// The unreferenced state group
+    pub state_group: i64,
+    /// The room the state group belongs to, or `None` if the state group is
+    /// referenced by another state group but is missing from `state_groups`
+    pub room_id: Option<String>,
+    /// The state group that this one points to, if any
+    pub prev_state_group: Option<i64>,
+}
+
+/// Counts of state groups for a single room.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RoomStats {
+    /// The room these counts are for
+    pub room_id: String,
+    /// The number of state groups inspected in the room
+    pub total_state_groups: u64,
+    /// The number of those that are not referenced
+    pub unreferenced_state_groups: u64,
+}
+
+#[derive(Default)]
+struct Entry {
+    /// The room the state group belongs to, if it exists in `state_groups`
+    room_id: Option<String>,
+    /// Any state groups that has this state group as a prev_group
+    next_state_groups: Vec<i64>,
+    /// The state group that this one points to, if any
+    prev_state_group: Option<i64>,
+    /// Whether an event references this state group or not
+    is_referenced: bool,
+}
+
+/// Scan the database for unreferenced state groups. If `room_id` is set then
+/// the scan is limited to state groups for that room (and any state groups in
+/// other rooms that they point to).
+///
+/// Progress is reported by calling `on_event`.
+pub fn scan(
+    db_url: &str,
+    room_id: Option<&str>,
+    mut on_event: impl FnMut(ScanEvent),
+) -> Result<ScanReport, ScanError> {
+    let mut conn = Client::connect(db_url, NoTls).map_err(ScanError::database)?;
+
+    // Fetch the initial set of groups from the DB.
+    let mut map = get_from_db(&mut conn, room_id, &mut on_event).map_err(ScanError::database)?;
+
+    on_event(ScanEvent::StateGroupsFetched(map.len()));
+
+    // Sometimes we'll be missing state groups that are referenced, so we
+    // iteratively find and fetch and missing state groups. This should only
+    // happen when a `room_id` has been specified.
+    let mut added: BTreeSet<i64> = map.keys().cloned().collect();
+    let mut missing = Vec::new();
+    loop {
+        missing.clear();
+
+        for sg in &added {
+            for next_sg in &map[sg].next_state_groups {
+                if !map.contains_key(next_sg) {
+                    missing.push(*next_sg);
+                }
+            }
+
+            if let Some(prev_sg) = map[sg].prev_state_group {
+                if !map.contains_key(&prev_sg) {
+                    missing.push(prev_sg);
+                }
+            }
+        }
+
+        if missing.is_empty() {
+            break;
+        }
+
+        missing.sort_unstable();
+        missing.dedup();
+
+        on_event(ScanEvent::FetchingMissing(missing.len()));
+
+        let updated = get_missing_from_db(&mut conn, &missing).map_err(ScanError::database)?;
+
+        on_event(ScanEvent::MissingFetched(updated.len()));
+
+        added.clear();
+        added.extend(updated.keys());
+
+        let missing_set: BTreeSet<i64> = missing.iter().cloned().collect();
+
+        let still_missing = missing_set.difference(&added).count();
+        if still_missing > 0 {
+            on_event(ScanEvent::MissingNotFound(still_missing));
+        }
+
+        map.extend(updated);
+    }
+
+    on_event(ScanEvent::TotalStateGroups(map.len()));
+
+    propagate_references(&mut map);
+
+    Ok(build_report(room_id, map))
+}
+
+/// Get state groups from the database. If `room_id` is set then its limited
+/// to state groups for that room
+fn get_from_db(
+    conn: &mut Client,
+    room_id: Option<&str>,
+    on_event: &mut dyn FnMut(ScanEvent),
+) -> Result<BTreeMap<i64, Entry>, postgres::Error> {
+    let mut sql = r#"
+        SELECT
+            main.id AS state_group,
+            forwards.state_group AS next,
+            backwards.prev_state_group AS prev,
+            EXISTS (SELECT 1 FROM event_to_state_groups WHERE state_group = main.id) AS is_referenced,
+            main.room_id AS room_id
+        FROM state_groups AS main
+        LEFT JOIN state_group_edges AS backwards ON (main.id = backwards.state_group)
+        LEFT JOIN state_group_edges AS forwards ON (main.id = forwards.prev_state_group)
+    "#.to_string();
+    let mut args: Vec<&(dyn ToSql + Sync)> = Vec::new();
+
+    if let Some(room_id) = &room_id {
+        sql.push_str(" WHERE room_id = $1");
+        args.push(room_id);
+    }
+
+    let stmt = conn.prepare(&sql)?;
+    let mut trans = conn.transaction()?;
+
+    let portal = trans.bind(&stmt, &args)?;
+
+    let mut state_group_map: BTreeMap<i64, Entry> = BTreeMap::new();
+
+    loop {
+        let rows = trans.query_portal(&portal, 10000)?;
+        if rows.is_empty() {
+            break;
+        }
+
+        on_event(ScanEvent::RowsRetrieved(rows.len() as u64));
+
+        for row in rows {
+            let state_group = row.get(0);
+
+            // We might get multiple rows per state_group due to having multiple
+            // next state groups.
+            let entry = state_group_map.entry(state_group).or_default();
+
+            if let Some(next_group) = row.get(1) {
+                entry.next_state_groups.push(next_group);
+            }
+
+            // These will all remain the same though.
+            entry.prev_state_group = row.get(2);
+            entry.is_referenced = row.get(3);
+            entry.room_id = row.get(4);
+        }
+    }
+
+    Ok(state_group_map)
+}
+
+/// Get any missing state groups from the database
+fn get_missing_from_db(
+    conn: &mut Client,
+    missing_sgs: &[i64],
+) -> Result<BTreeMap<i64, Entry>, postgres::Error> {
+    let stmt = conn.prepare(
+        r#"
+            SELECT
+                main.id AS state_group,
+                forwards.state_group AS next,
+                backwards.prev_state_group AS prev,
+                EXISTS (SELECT 1 FROM event_to_state_groups WHERE state_group = main.id) AS is_referenced,
+                (SELECT room_id FROM state_groups WHERE id = main.id) AS room_id
+            FROM (SELECT $1::bigint AS id) AS main
+            LEFT JOIN state_group_edges AS backwards ON (main.id = backwards.state_group)
+            LEFT JOIN state_group_edges AS forwards ON (main.id = forwards.prev_state_group)
+        "#,
+    )?;
+
+    let mut state_group_map: BTreeMap<i64, Entry> = BTreeMap::new();
+
+    for missing_sg in missing_sgs {
+        let rows = conn.query(&stmt, &[&missing_sg])?;
+
+        for row in &rows {
+            let state_group = row.get(0);
+
+            // We might get multiple rows per state_group due to having multiple
+            // next state groups.
+            let entry = state_group_map.entry(state_group).or_default();
+
+            if let Some(next_group) = row.get(1) {
+                entry.next_state_groups.push(next_group);
+            }
+
+            // These will all remain the same though.
+            entry.prev_state_group = row.get(2);
+            entry.is_referenced = row.get(3);
+            entry.room_id = row.get(4);
+        }
+    }
+
+    Ok(state_group_map)
+}
+
+/// Propagate the referenced flag, i.e. if a state group is referenced then its
+/// prev group should also be marked as referenced, recursively.
+fn propagate_references(map: &mut BTreeMap<i64, Entry>) {
+    for state_group in map.keys().cloned().collect::<Vec<_>>() {
+        let mut next = {
+            let entry = &map[&state_group];
+            if !entry.is_referenced {
+                continue;
+            }
+
+            entry.prev_state_group
+        };
+
+        while let Some(sg) = next.take() {
+            let entry = map.get_mut(&sg).unwrap();
+            if !entry.is_referenced {
+                entry.is_referenced = true;
+                next = entry.prev_state_group;
+            }
+        }
+    }
+}
+
+fn build_report(room_id: Option<&str>, map: BTreeMap<i64, Entry>) -> ScanReport {
+    let total_state_groups = map.len() as u64;
+
+    let mut candidates = Vec::new();
+    let mut rooms: BTreeMap<String, RoomStats> = BTreeMap::new();
+
+    for (state_group, entry) in map {
+        // State groups missing from `state_groups` don't belong to any room,
+        // so are only included in the totals.
+        if let Some(room_id) = &entry.room_id {
+            let stats = rooms.entry(room_id.clone()).or_insert_with(|| RoomStats {
+                room_id: room_id.clone(),
+                total_state_groups: 0,
+                unreferenced_state_groups: 0,
+            });
+
+            stats.total_state_groups += 1;
+            if !entry.is_referenced {
+                stats.unreferenced_state_groups += 1;
+            }
+        }
+
+        if !entry.is_referenced {
+            candidates.push(Candidate {
+                state_group,
+                room_id: entry.room_id,
+                prev_state_group: entry.prev_state_group,
+            });
+        }
+    }
+
+    ScanReport {
+        version: SCAN_REPORT_VERSION,
+        room_id: room_id.map(str::to_string),
+        total_state_groups,
+        candidates,
+        rooms: rooms.into_values().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    /// Build a map from `(state_group, room_id, prev_state_group, is_referenced)`
+    /// tuples, filling in `next_state_groups` from the prev links.
+    fn make_map(groups: &[(i64, Option<&str>, Option<i64>, bool)]) -> BTreeMap<i64, Entry> {
+        let mut map: BTreeMap<i64, Entry> = BTreeMap::new();
+
+        for &(state_group, room_id, prev_state_group, is_referenced) in groups {
+            let entry = map.entry(state_group).or_default();
+            entry.room_id = room_id.map(str::to_string);
+            entry.prev_state_group = prev_state_group;
+            entry.is_referenced = is_referenced;
+        }
+
+        for &(state_group, _, prev_state_group, _) in groups {
+            if let Some(prev) = prev_state_group {
+                map.entry(prev)
+                    .or_default()
+                    .next_state_groups
+                    .push(state_group);
+            }
+        }
+
+        map
+    }
+
+    #[test]
+    fn reference_propagates_along_prev_chain() {
+        let mut map = make_map(&[
+            (1, Some("!a"), None, false),
+            (2, Some("!a"), Some(1), false),
+            (3, Some("!a"), Some(2), true),
+            (4, Some("!a"), Some(3), false),
+        ]);
+
+        propagate_references(&mut map);
+
+        assert!(map[&1].is_referenced);
+        assert!(map[&2].is_referenced);
+        assert!(map[&3].is_referenced);
+        assert!(!map[&4].is_referenced);
+    }
+
+    #[test]
+    fn room_stats_are_counted_per_room() {
+        let mut map = make_map(&[
+            (1, Some("!a"), None, true),
+            (2, Some("!a"), None, false),
+            (3, Some("!b"), None, false),
+            (4, None, None, false),
+        ]);
+
+        propagate_references(&mut map);
+        let report = build_report(None, map);
+
+        assert_eq!(report.total_state_groups, 4);
+        assert_eq!(
+            report.rooms,
+            vec![
+                RoomStats {
+                    room_id: "!a".to_string(),
+                    total_state_groups: 2,
+                    unreferenced_state_groups: 1,
+                },
+                RoomStats {
+                    room_id: "!b".to_string(),
+                    total_state_groups: 1,
+                    unreferenced_state_groups: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn candidates_are_ordered_by_state_group() {
+        let mut map = make_map(&[
+            (30, Some("!a"), None, false),
+            (10, Some("!b"), None, false),
+            (20, Some("!a"), Some(10), true),
+            (5, Some("!b"), None, false),
+        ]);
+
+        propagate_references(&mut map);
+        let report = build_report(None, map);
+
+        let state_groups: Vec<i64> = report.candidates.iter().map(|c| c.state_group).collect();
+        assert_eq!(state_groups, vec![5, 30]);
+    }
+
+    #[test]
+    fn report_serialization_shape() {
+        let mut map = make_map(&[
+            (1, Some("!a"), None, true),
+            (2, Some("!a"), Some(1), false),
+            (3, None, None, false),
+        ]);
+
+        propagate_references(&mut map);
+        let report = build_report(Some("!a"), map);
+
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "version": 1,
+                "room_id": "!a",
+                "total_state_groups": 3,
+                "candidates": [
+                    { "state_group": 2, "room_id": "!a", "prev_state_group": 1 },
+                    { "state_group": 3, "room_id": null, "prev_state_group": null },
+                ],
+                "rooms": [
+                    {
+                        "room_id": "!a",
+                        "total_state_groups": 2,
+                        "unreferenced_state_groups": 1,
+                    },
+                ],
+            })
+        );
+
+        let round_tripped: ScanReport = serde_json::from_value(value).unwrap();
+        assert_eq!(round_tripped, report);
+    }
+}